@@ -0,0 +1,119 @@
+//! Header lookups on a 1 MB mail.
+//!
+//! Run with `cargo bench`. This times a plain loop instead of using a benchmark framework, so
+//! it doesn't need any extra dependencies.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use aho_corasick::{AhoCorasickBuilder, MatchKind};
+use smtp_filter::{utils, BasicMail, UnparsedMail};
+
+/// The headers read in each run. The last one is missing, so every header line is scanned.
+const HEADERS: [&str; 10] = [
+    "subject",
+    "from",
+    "to",
+    "date",
+    "message-id",
+    "received",
+    "content-type",
+    "x-spam-status",
+    "list-id",
+    "x-missing",
+];
+
+/// Run `f` for about a second and print the mean time of a run.
+fn bench(name: &str, mut f: impl FnMut()) {
+    f();
+    let start = Instant::now();
+    let mut runs = 0;
+    while start.elapsed() < Duration::from_secs(1) {
+        f();
+        runs += 1;
+    }
+    println!("{name:<40} {:>12.2?} ({runs} runs)", start.elapsed() / runs);
+}
+
+/// A 1 MB mail with `hops` `Received` headers.
+fn large_mail(hops: usize) -> UnparsedMail {
+    let mut raw = String::new();
+    for hop in 0..hops {
+        raw += &format!(
+            "Received: from relay{hop}.example.org (relay{hop}.example.org [192.0.2.{hop}])\r\n\
+             \tby mx.example.com with ESMTPS id {hop:08X}; Tue, 1 Jul 2003 10:52:37 +0200\r\n"
+        );
+    }
+    raw += "From: Alice <alice@example.com>\r\n\
+            To: Bob <bob@example.org>\r\n\
+            Subject: A large mail\r\n\
+            Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+            Message-ID: <large@example.com>\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            X-Spam-Status: No, score=-0.1\r\n\
+            List-Id: <users.example.org>\r\n\
+            \r\n";
+    // the header names also appear in the body, which mustn't be searched
+    let line = "To: someone in the body. Subject: lorem ipsum dolor sit amet.\r\n";
+    while raw.len() < 1024 * 1024 {
+        raw += line;
+    }
+    UnparsedMail::new(
+        raw,
+        utils::addr_single("alice@example.com"),
+        utils::addr_single("bob@example.org"),
+    )
+}
+
+/// The lookup [`BasicMail::header_value`] replaced: an automaton of the header name and the
+/// end of the headers is built for each lookup.
+fn automaton_header_value(contents: &[u8], header: &str) -> Option<String> {
+    let header = format!("\n{header}:");
+    let name = &header.as_bytes()[1..];
+    let start = if contents
+        .get(..name.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(name))
+    {
+        0
+    } else {
+        let needle = AhoCorasickBuilder::new()
+            .ascii_case_insensitive(true)
+            .match_kind(MatchKind::LeftmostFirst)
+            .build(["\r\n\r\n", "\n\n", &header])
+            .unwrap();
+        let first = needle.find(contents)?;
+        if first.pattern().as_i32() != 2 {
+            return None;
+        }
+        first.start() + 1
+    };
+    let (header, _) = mailparse::parse_header(&contents[start..]).ok()?;
+    Some(header.get_value())
+}
+
+fn main() {
+    let mut mail = large_mail(8);
+    for header in HEADERS {
+        let expected = automaton_header_value(mail.contents(), header);
+        assert_eq!(mail.header_value(header), expected, "{header}");
+    }
+    bench("10 header reads (automaton per lookup)", || {
+        for header in HEADERS {
+            black_box(automaton_header_value(mail.contents(), header));
+        }
+    });
+    bench("10 header reads", || {
+        for header in HEADERS {
+            black_box(mail.header_value(header));
+        }
+    });
+    let mut many_hops = large_mail(200);
+    bench("10 header reads, 200 Received headers", || {
+        for header in HEADERS {
+            black_box(many_hops.header_value(header));
+        }
+    });
+    bench("received_count, 200 Received headers", || {
+        black_box(many_hops.received_count());
+    });
+}
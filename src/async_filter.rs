@@ -0,0 +1,256 @@
+//! An asynchronous [`Filter`](crate::Filter), see [`AsyncFilter`].
+
+use std::collections::BTreeMap;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Action, BasicMail, Error, Outcome};
+
+/// A boxed [`Future`] borrowing the mail, returned from the filters of [`AsyncFilter`].
+///
+/// Create it using `Box::pin(async move { ... })`.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type AsyncFilterFn<M> = Box<dyn for<'a> Fn(&'a mut M) -> BoxFuture<'a, Action> + Send + Sync>;
+
+/// The state of a deadline registered in the [`Timer`].
+#[derive(Default)]
+struct DeadlineState {
+    expired: bool,
+    /// Woken when the deadline expires.
+    waker: Option<Waker>,
+}
+/// When a deadline expires. The `u64` makes equal instants unique.
+type DeadlineKey = (Instant, u64);
+/// A single thread expiring the deadlines of [`with_deadline`], so no thread is started per
+/// filter.
+struct Timer {
+    /// The deadlines, ordered by when they expire.
+    deadlines: Mutex<BTreeMap<DeadlineKey, Arc<Mutex<DeadlineState>>>>,
+    /// Notified when a deadline is added, as it might expire before the others.
+    added: Condvar,
+    next_id: AtomicU64,
+}
+impl Timer {
+    fn get() -> &'static Self {
+        static TIMER: OnceLock<Timer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            thread::Builder::new()
+                .name("smtp-filter-timer".to_owned())
+                .spawn(|| Self::get().run())
+                .expect("failed to spawn timer thread");
+            Self {
+                deadlines: Mutex::new(BTreeMap::new()),
+                added: Condvar::new(),
+                next_id: AtomicU64::new(0),
+            }
+        })
+    }
+    fn run(&self) {
+        loop {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            let now = Instant::now();
+            let mut expired = Vec::new();
+            while let Some(entry) = deadlines.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                expired.push(entry.remove());
+            }
+            if expired.is_empty() {
+                let _guard = match deadlines.keys().next() {
+                    Some(&(deadline, _)) => {
+                        self.added
+                            .wait_timeout(deadlines, deadline - now)
+                            .unwrap()
+                            .0
+                    }
+                    None => self.added.wait(deadlines).unwrap(),
+                };
+                continue;
+            }
+            drop(deadlines);
+            for state in expired {
+                let mut state = state.lock().unwrap();
+                state.expired = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+/// A deadline registered in the [`Timer`], removed from it when dropped.
+struct Deadline {
+    key: DeadlineKey,
+    state: Arc<Mutex<DeadlineState>>,
+}
+impl Deadline {
+    fn new(timeout: Duration) -> Self {
+        let timer = Timer::get();
+        let key = (
+            Instant::now() + timeout,
+            timer.next_id.fetch_add(1, Ordering::Relaxed),
+        );
+        let state = Arc::default();
+        timer
+            .deadlines
+            .lock()
+            .unwrap()
+            .insert(key, Arc::clone(&state));
+        timer.added.notify_one();
+        Self { key, state }
+    }
+}
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        Timer::get().deadlines.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Run `future`, returning `None` if it doesn't complete within `timeout`.
+///
+/// The deadline is expired by a single shared timer thread, so this works with any executor.
+/// It's cancelled when `future` completes first.
+async fn with_deadline<T>(mut future: BoxFuture<'_, T>, timeout: Duration) -> Option<T> {
+    let deadline = Deadline::new(timeout);
+    future::poll_fn(move |cx| {
+        if let Poll::Ready(v) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(v));
+        }
+        let mut state = deadline.state.lock().unwrap();
+        if state.expired {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    })
+    .await
+}
+
+/// Like [`Filter`](crate::Filter), but the filters are asynchronous.
+///
+/// Useful when the filters do network I/O, such as DNS lookups.
+pub struct AsyncFilter<M: BasicMail> {
+    /// The filters, and if they change the mail (set by [`AsyncFilter::map`]).
+    filters: Vec<(AsyncFilterFn<M>, bool)>,
+    /// Set by [`AsyncFilter::with_timeout`].
+    timeout: Option<(Duration, Error)>,
+}
+impl<M: BasicMail + Send> AsyncFilter<M> {
+    pub fn new() -> Self {
+        Self {
+            filters: Vec::new(),
+            timeout: None,
+        }
+    }
+    /// Limit the time each filter can take to `timeout`, so a slow filter (e.g. a stalled
+    /// lookup) can't hang the SMTP session past the client's timeout.
+    ///
+    /// A filter which doesn't complete in time is stopped and acts as if it rejected the mail
+    /// with `err`, which should be [temporary](Error::is_temporary) (e.g.
+    /// [`Error::temporary_failure`]) so the mail is deferred and retried. Changes the filter made
+    /// to the mail before it was stopped are kept.
+    pub fn with_timeout(&mut self, timeout: Duration, err: Error) -> &mut Self {
+        self.timeout = Some((timeout, err));
+        self
+    }
+
+    /// Filter the mail
+    ///
+    /// See [`Filter::filter`](crate::Filter::filter).
+    pub fn filter<V: Into<Action> + 'static>(
+        &mut self,
+        filter: impl for<'a> Fn(&'a mut M) -> BoxFuture<'a, V> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.filters.push((
+            Box::new(move |mail| {
+                let future = filter(mail);
+                Box::pin(async move { future.await.into() })
+            }),
+            false,
+        ));
+        self
+    }
+    /// Either continue or reject mail
+    pub fn and_then(
+        &mut self,
+        f: impl for<'a> Fn(&'a mut M) -> BoxFuture<'a, Result<(), Error>> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.filter(f)
+    }
+    /// Change mail contents
+    pub fn map(
+        &mut self,
+        f: impl for<'a> Fn(&'a mut M) -> BoxFuture<'a, ()> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.filter(move |mail| {
+            let future = f(mail);
+            Box::pin(async move {
+                future.await;
+                true
+            })
+        });
+        self.filters.last_mut().unwrap().1 = true;
+        self
+    }
+
+    /// Filter a mail and return the result.
+    ///
+    /// See [`Filter::process`](crate::Filter::process).
+    pub async fn process(&self, mut mail: M) -> Outcome {
+        let mut ignored = false;
+        for (idx, (filter, mutating)) in self.filters.iter().enumerate() {
+            if ignored && !mutating {
+                log::info!("Skipping check n:r {} of ignored mail", idx + 1);
+                continue;
+            }
+            log::info!("Running transformation n:r {}", idx + 1);
+            let action = match &self.timeout {
+                Some((timeout, err)) => with_deadline(filter(&mut mail), *timeout)
+                    .await
+                    .unwrap_or_else(|| {
+                        log::info!("Timed out after {timeout:?}");
+                        Action::Reject(err.clone())
+                    }),
+                None => filter(&mut mail).await,
+            };
+            match action {
+                Action::Continue => {
+                    log::info!("Continue!");
+                }
+                Action::Ignore => {
+                    log::info!("Filtered out at n:r {}", idx + 1);
+                    ignored = true;
+                }
+                Action::Quarantine { reason } => {
+                    log::info!("Quarantine at n:r {}: {reason}", idx + 1);
+                    return Outcome::Quarantined {
+                        reason,
+                        mail: mail.into_parts(),
+                    };
+                }
+                Action::Reject(err) => {
+                    log::info!("Reject at n:r {}: {err}", idx + 1);
+                    if err.is_temporary() {
+                        return Outcome::Deferred(err.to_string());
+                    }
+                    return Outcome::Rejected(err.to_string());
+                }
+            }
+        }
+        let parts = mail.into_parts();
+        log::info!("{parts}");
+        Outcome::Delivered(parts)
+    }
+}
+impl<M: BasicMail + Send> Default for AsyncFilter<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,190 @@
+//! Parsing of the authentication verdicts added by the mail server.
+
+/// The result of a SPF check, see [`BasicMail::spf_result`](crate::BasicMail::spf_result).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpfResult {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    None,
+    TempError,
+    PermError,
+}
+impl SpfResult {
+    /// Parse a SPF verdict, such as `pass` or `softfail` (case-insensitive).
+    pub fn from_token(token: &str) -> Option<Self> {
+        let result = match token.to_ascii_lowercase().as_str() {
+            "pass" => Self::Pass,
+            "fail" => Self::Fail,
+            "softfail" => Self::SoftFail,
+            "neutral" => Self::Neutral,
+            "none" => Self::None,
+            "temperror" => Self::TempError,
+            "permerror" => Self::PermError,
+            _ => return None,
+        };
+        Some(result)
+    }
+    /// Parse the value of a `Received-SPF` header.
+    ///
+    /// The verdict is the first word, anything after it (usually a comment) is ignored.
+    pub fn from_received_spf(value: &str) -> Option<Self> {
+        let token = value.split_whitespace().next()?;
+        // `pass(comment)` without whitespace
+        let token = token.split('(').next().unwrap_or(token);
+        Self::from_token(token)
+    }
+}
+
+/// How the envelope sender's domain matches the `From` header's domain, see
+/// [`BasicMail::from_alignment`](crate::BasicMail::from_alignment).
+///
+/// This is similar to DMARC identifier alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// The domains are equal (case-insensitive).
+    Strict,
+    /// One domain is a subdomain of the other, e.g. `mail.example.org` & `example.org`.
+    ///
+    /// DMARC uses the organizational domains, which requires the public suffix list. This
+    /// doesn't, so e.g. `example.co.uk` & `other.co.uk` are unaligned.
+    Relaxed,
+    /// The domains are unrelated, a common sign of phishing.
+    Unaligned,
+    /// Either domain is missing, e.g. for bounces with an empty envelope sender.
+    Unknown,
+}
+impl Alignment {
+    /// Get the alignment of the domains `a` & `b`.
+    pub fn of(a: &str, b: &str) -> Self {
+        let a = a.trim_end_matches('.').to_ascii_lowercase();
+        let b = b.trim_end_matches('.').to_ascii_lowercase();
+        let is_subdomain =
+            |sub: &str, parent: &str| sub.strip_suffix(parent).is_some_and(|s| s.ends_with('.'));
+        if a.is_empty() || b.is_empty() {
+            Self::Unknown
+        } else if a == b {
+            Self::Strict
+        } else if is_subdomain(&a, &b) || is_subdomain(&b, &a) {
+            Self::Relaxed
+        } else {
+            Self::Unaligned
+        }
+    }
+    /// If the domains are [strictly](Self::Strict) or [relaxed](Self::Relaxed) aligned.
+    pub fn is_aligned(self) -> bool {
+        matches!(self, Self::Strict | Self::Relaxed)
+    }
+}
+
+/// A verdict of a method in an `Authentication-Results` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthVerdict {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    None,
+    Policy,
+    TempError,
+    PermError,
+}
+impl AuthVerdict {
+    /// Parse a verdict, such as `pass` or `fail` (case-insensitive).
+    pub fn from_token(token: &str) -> Option<Self> {
+        let verdict = match token.to_ascii_lowercase().as_str() {
+            "pass" => Self::Pass,
+            "fail" => Self::Fail,
+            "softfail" => Self::SoftFail,
+            "neutral" => Self::Neutral,
+            "none" => Self::None,
+            "policy" => Self::Policy,
+            "temperror" => Self::TempError,
+            "permerror" => Self::PermError,
+            _ => return None,
+        };
+        Some(verdict)
+    }
+}
+
+/// The parsed value of an `Authentication-Results` header.
+///
+/// See [`BasicMail::auth_results`](crate::BasicMail::auth_results).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthResults {
+    /// The `authserv-id`, identifying the server which added the header.
+    pub authserv_id: String,
+    pub dkim: Option<AuthVerdict>,
+    /// The signing domain (`header.d`) of the DKIM result.
+    pub dkim_domain: Option<String>,
+    pub spf: Option<AuthVerdict>,
+    pub dmarc: Option<AuthVerdict>,
+}
+impl AuthResults {
+    /// Parse the value of an `Authentication-Results` header.
+    ///
+    /// If there are multiple DKIM results, the first passing one is used.
+    /// Unknown methods are ignored.
+    pub fn parse(value: &str) -> Self {
+        let value = strip_comments(value);
+        let mut parts = value.split(';');
+        let mut results = Self {
+            authserv_id: parts
+                .next()
+                .and_then(|id| id.split_whitespace().next())
+                .unwrap_or_default()
+                .to_owned(),
+            ..Default::default()
+        };
+        for part in parts {
+            let mut tokens = part.split_whitespace();
+            let Some((method, verdict)) = tokens.next().and_then(|t| t.split_once('=')) else {
+                continue;
+            };
+            let Some(verdict) = AuthVerdict::from_token(verdict) else {
+                continue;
+            };
+            // `method/version`
+            let method = method.split('/').next().unwrap_or(method);
+            match method.to_ascii_lowercase().as_str() {
+                "dkim" => {
+                    if results.dkim == Some(AuthVerdict::Pass) {
+                        continue;
+                    }
+                    results.dkim = Some(verdict);
+                    results.dkim_domain = tokens
+                        .filter_map(|t| t.split_once('='))
+                        .find(|(property, _)| property.eq_ignore_ascii_case("header.d"))
+                        .map(|(_, domain)| domain.to_owned());
+                }
+                "spf" => {
+                    results.spf.get_or_insert(verdict);
+                }
+                "dmarc" => {
+                    results.dmarc.get_or_insert(verdict);
+                }
+                _ => {}
+            }
+        }
+        results
+    }
+}
+/// Remove (possibly nested) comments in parentheses.
+fn strip_comments(value: &str) -> String {
+    let mut depth = 0_usize;
+    value
+        .chars()
+        .filter(|&c| {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    return false;
+                }
+                _ => {}
+            }
+            depth == 0
+        })
+        .collect()
+}
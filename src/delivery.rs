@@ -0,0 +1,33 @@
+//! Re-injecting the filtered mail into the mail server.
+
+use std::io::{self, Write};
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::MailParts;
+
+#[cfg(all(feature = "lmtp", unix))]
+pub mod lmtp;
+
+/// Spawn `sendmail_path` with the [arguments](MailParts::to_sendmail_args) of `parts`, write the
+/// body to its stdin, and wait for it to exit.
+///
+/// sendmail is waited for even if writing the body fails, e.g. because it exited early. The
+/// write error is then returned.
+///
+/// `sendmail_path` is usually `sendmail` or `/usr/sbin/sendmail`.
+pub fn pipe_to_sendmail(parts: MailParts, sendmail_path: &str) -> io::Result<ExitStatus> {
+    let args = parts.to_sendmail_args();
+    log::info!("Running {sendmail_path} {}", args.join(" "));
+    let mut child = Command::new(sendmail_path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let written = stdin.write_all(&parts.body).and_then(|()| stdin.flush());
+    // close stdin so sendmail knows the mail has ended
+    drop(stdin);
+    // always wait, so sendmail isn't left as a zombie if it exited early
+    let status = child.wait();
+    written?;
+    status
+}
@@ -0,0 +1,194 @@
+//! Re-injecting the filtered mail over LMTP, e.g. to Dovecot, instead of spawning `sendmail`.
+
+use std::fmt::{self, Display};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::{utils, MailParts};
+
+/// Error when delivering a mail using [`deliver_lmtp`].
+#[derive(Debug)]
+pub enum LmtpError {
+    /// Connecting to, reading from or writing to the socket failed.
+    Io(io::Error),
+    /// The server sent a reply which isn't valid LMTP.
+    InvalidReply(String),
+    /// The sender or a recipient contains control characters (e.g. line breaks) or `<>`, so it
+    /// can't be sent without changing the commands. Nothing is sent.
+    InvalidAddress(String),
+    /// The server rejected a command. `recipient` is set if the rejection only concerns that
+    /// recipient.
+    Rejected {
+        recipient: Option<String>,
+        code: u16,
+        message: String,
+    },
+}
+impl Display for LmtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "LMTP connection failed: {err}"),
+            Self::InvalidReply(reply) => write!(f, "invalid LMTP reply: {reply:?}"),
+            Self::InvalidAddress(addr) => write!(f, "invalid address for LMTP: {addr:?}"),
+            Self::Rejected {
+                recipient: Some(recipient),
+                code,
+                message,
+            } => write!(f, "LMTP server rejected {recipient}: {code} {message}"),
+            Self::Rejected {
+                recipient: None,
+                code,
+                message,
+            } => write!(f, "LMTP server rejected the mail: {code} {message}"),
+        }
+    }
+}
+impl std::error::Error for LmtpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for LmtpError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+impl Connection {
+    fn send(&mut self, command: &str) -> Result<(), LmtpError> {
+        log::info!("LMTP: {command}");
+        self.writer.write_all(command.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        Ok(())
+    }
+    /// Read a (possibly multiline) reply, returning the code and the text of the last line.
+    fn reply(&mut self) -> Result<(u16, String), LmtpError> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(LmtpError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| LmtpError::InvalidReply(line.to_owned()))?;
+            match line.as_bytes().get(3) {
+                Some(b'-') => continue,
+                None | Some(b' ') => return Ok((code, line.get(4..).unwrap_or("").to_owned())),
+                Some(_) => return Err(LmtpError::InvalidReply(line.to_owned())),
+            }
+        }
+    }
+    /// Read a reply, which must be positive (2xx or 3xx).
+    fn expect(&mut self, recipient: Option<&str>) -> Result<u16, LmtpError> {
+        let (code, message) = self.reply()?;
+        if (200..400).contains(&code) {
+            Ok(code)
+        } else {
+            log::info!("LMTP server replied {code} {message}");
+            Err(LmtpError::Rejected {
+                recipient: recipient.map(str::to_owned),
+                code,
+                message,
+            })
+        }
+    }
+}
+
+/// Check that `addr` can be put in `MAIL FROM:<>` or `RCPT TO:<>`, so it can't inject commands.
+fn check_address(addr: &str) -> Result<(), LmtpError> {
+    if addr.contains(|c: char| c.is_control() || c == '<' || c == '>') {
+        log::info!("Invalid LMTP address {addr:?}");
+        return Err(LmtpError::InvalidAddress(addr.to_owned()));
+    }
+    Ok(())
+}
+
+/// Write `body` with CRLF line endings and leading dots doubled, as `DATA` requires.
+fn write_data(out: &mut impl Write, body: &[u8]) -> io::Result<()> {
+    let mut lines = body.split(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if lines.peek().is_none() && line.is_empty() {
+            break;
+        }
+        if line.first() == Some(&b'.') {
+            out.write_all(b".")?;
+        }
+        out.write_all(line)?;
+        out.write_all(b"\r\n")?;
+    }
+    out.write_all(b".\r\n")
+}
+
+/// Deliver `parts` to the LMTP server listening on the unix `socket`.
+///
+/// The sender is taken from `parts`, but the mail is delivered to `recipients`, as the LMTP
+/// server often expects local users rather than the envelope recipients. Pass the addresses of
+/// [`MailParts::to`] to use those.
+///
+/// LMTP replies once per recipient after the mail is sent: the delivery fails if any recipient
+/// is rejected, even if the mail was delivered to the others.
+///
+/// The addresses are checked before connecting, see [`LmtpError::InvalidAddress`].
+pub fn deliver_lmtp(
+    parts: &MailParts,
+    socket: &Path,
+    recipients: &[&str],
+) -> Result<(), LmtpError> {
+    let from = utils::iter_addrs(&parts.from)
+        .next()
+        .map_or("", |from| from.addr.as_str());
+    check_address(from)?;
+    for recipient in recipients {
+        check_address(recipient)?;
+    }
+
+    log::info!("Connecting to LMTP socket {}", socket.display());
+    let stream = UnixStream::connect(socket)?;
+    let mut conn = Connection {
+        reader: BufReader::new(stream.try_clone()?),
+        writer: stream,
+    };
+    conn.expect(None)?;
+    conn.send("LHLO localhost")?;
+    conn.expect(None)?;
+
+    conn.send(&format!("MAIL FROM:<{from}>"))?;
+    conn.expect(None)?;
+    for recipient in recipients {
+        conn.send(&format!("RCPT TO:<{recipient}>"))?;
+        conn.expect(Some(recipient))?;
+    }
+    conn.send("DATA")?;
+    conn.expect(None)?;
+
+    let mut data = Vec::with_capacity(parts.body.len() + 64);
+    write_data(&mut data, &parts.body)?;
+    conn.writer.write_all(&data)?;
+    conn.writer.flush()?;
+    // one reply per accepted recipient
+    let mut result = Ok(());
+    for recipient in recipients {
+        if let Err(err) = conn.expect(Some(recipient)) {
+            if matches!(err, LmtpError::Io(_) | LmtpError::InvalidReply(_)) {
+                return Err(err);
+            }
+            result = result.and(Err(err));
+        }
+    }
+
+    conn.send("QUIT")?;
+    // the server closes the connection, so the reply isn't important
+    let _ = conn.reply();
+    result
+}
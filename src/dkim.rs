@@ -0,0 +1,555 @@
+//! DKIM signing & verification (RFC 6376).
+//!
+//! Changing the headers or body of a mail invalidates the original signature. Signing the mail
+//! again with the key of a domain you control lets the receiver verify it.
+//! [Verifying](verify_with_key) signatures yourself avoids trusting `Authentication-Results`.
+//!
+//! Only `rsa-sha256` with `relaxed/relaxed` canonicalization is supported.
+
+use std::fmt::{self, Display};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+use crate::{detect_line_ending, Action, BasicMail, Error, Filter, MailParts, UnparsedMail};
+
+/// Error when signing a mail.
+#[derive(Debug)]
+pub enum DkimError {
+    /// The private key isn't a RSA key in PKCS #8 or PKCS #1 format (PEM or DER).
+    InvalidKey,
+    /// The headers of the mail couldn't be parsed.
+    InvalidMail(mailparse::MailParseError),
+    /// Signing failed.
+    Signing(rsa::Error),
+}
+impl Display for DkimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidKey => f.write_str("invalid DKIM private key"),
+            Self::InvalidMail(err) => write!(f, "invalid mail: {err}"),
+            Self::Signing(err) => write!(f, "DKIM signing failed: {err}"),
+        }
+    }
+}
+impl std::error::Error for DkimError {}
+
+/// Parse a RSA private key, in PKCS #8 or PKCS #1 format, either PEM or DER.
+pub fn parse_private_key(private_key: &[u8]) -> Result<RsaPrivateKey, DkimError> {
+    let key = match std::str::from_utf8(private_key) {
+        Ok(pem) => RsaPrivateKey::from_pkcs8_pem(pem)
+            .ok()
+            .or_else(|| RsaPrivateKey::from_pkcs1_pem(pem).ok()),
+        Err(_) => RsaPrivateKey::from_pkcs8_der(private_key)
+            .ok()
+            .or_else(|| RsaPrivateKey::from_pkcs1_der(private_key).ok()),
+    };
+    key.ok_or(DkimError::InvalidKey)
+}
+
+/// Reduce all runs of whitespace in `value` to a single space, appending it to `out`.
+///
+/// Leading whitespace is kept as a single space if `keep_leading`. Trailing whitespace and line
+/// breaks are removed.
+fn compress_whitespace(value: &[u8], keep_leading: bool, out: &mut Vec<u8>) {
+    let start = out.len();
+    let mut whitespace = false;
+    for &b in value {
+        match b {
+            b'\r' | b'\n' => {}
+            b' ' | b'\t' => whitespace = true,
+            _ => {
+                if whitespace && (keep_leading || out.len() > start) {
+                    out.push(b' ');
+                }
+                whitespace = false;
+                out.push(b);
+            }
+        }
+    }
+}
+/// Canonicalize a header using the `relaxed` algorithm, including the trailing CRLF.
+fn canonicalize_header(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut out = name.trim().to_ascii_lowercase().into_bytes();
+    out.push(b':');
+    compress_whitespace(value, false, &mut out);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+/// Canonicalize the body using the `relaxed` algorithm.
+///
+/// Lines may end with `\n` or `\r\n`; the result always uses `\r\n`.
+fn canonicalize_body(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut empty_lines = 0;
+    let mut line = Vec::new();
+    for raw_line in body.split(|&b| b == b'\n') {
+        line.clear();
+        compress_whitespace(raw_line, true, &mut line);
+        if line.is_empty() {
+            // only kept if followed by a non-empty line
+            empty_lines += 1;
+            continue;
+        }
+        for _ in 0..empty_lines {
+            out.extend_from_slice(b"\r\n");
+        }
+        empty_lines = 0;
+        out.extend_from_slice(&line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Get the value of a `DKIM-Signature` header for `message` (the headers & body), signed by
+/// `key` for `domain` with `selector`.
+///
+/// `headers` are the names of the headers to sign, e.g. `["from", "to", "subject", "date"]`.
+/// They must include `from`, or verifiers reject the signature (see [`verify_with_key`]).
+/// Repeated headers are signed bottom-up, as the RFC requires. The value is folded using
+/// `line_ending`.
+pub fn signature(
+    message: &[u8],
+    selector: &str,
+    domain: &str,
+    key: &RsaPrivateKey,
+    headers: &[&str],
+    line_ending: &str,
+) -> Result<String, DkimError> {
+    let (parsed, body_start) = mailparse::parse_headers(message).map_err(DkimError::InvalidMail)?;
+    let body_hash = Sha256::digest(canonicalize_body(&message[body_start..]));
+    let body_hash = base64::engine::general_purpose::STANDARD.encode(body_hash);
+
+    let mut used = vec![false; parsed.len()];
+    let mut data = Vec::new();
+    for name in headers {
+        // the last unused occurrence, so repeated names sign every occurrence bottom-up
+        let Some(idx) = (0..parsed.len())
+            .rev()
+            .find(|&idx| !used[idx] && parsed[idx].get_key_ref().eq_ignore_ascii_case(name))
+        else {
+            continue;
+        };
+        used[idx] = true;
+        data.extend(canonicalize_header(name, parsed[idx].get_value_raw()));
+    }
+
+    let timestamp = time::OffsetDateTime::now_utc().unix_timestamp();
+    let mut value = format!(
+        "v=1; a=rsa-sha256; c=relaxed/relaxed; d={domain}; s={selector};{line_ending}\t\
+        t={timestamp}; h={};{line_ending}\tbh={body_hash};{line_ending}\tb=",
+        headers.join(":"),
+    );
+    let mut signature_header = canonicalize_header("dkim-signature", value.as_bytes());
+    // the signature header itself is signed without the trailing CRLF
+    signature_header.truncate(signature_header.len() - 2);
+    data.extend(signature_header);
+
+    let signature = key
+        .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&data))
+        .map_err(DkimError::Signing)?;
+    value += &base64::engine::general_purpose::STANDARD.encode(signature);
+    log::info!("Signed mail for {domain} with selector {selector}");
+    Ok(value)
+}
+/// Sign the mail in `parts` and prepend the `DKIM-Signature` header, see [`signature`].
+///
+/// `private_key` is parsed using [`parse_private_key`]. Sign after all changes to the mail.
+pub fn sign(
+    parts: &mut MailParts,
+    selector: &str,
+    domain: &str,
+    private_key: &[u8],
+    headers: &[&str],
+) -> Result<(), DkimError> {
+    let key = parse_private_key(private_key)?;
+    let line_ending = detect_line_ending(&parts.body);
+    let value = signature(&parts.body, selector, domain, &key, headers, line_ending)?;
+    let header = format!("DKIM-Signature: {value}{line_ending}");
+    parts.body.splice(0..0, header.into_bytes());
+    Ok(())
+}
+
+impl Filter<UnparsedMail> {
+    /// Sign the mail, see [`sign`]. Add this after all filters changing the mail.
+    ///
+    /// The key is parsed once, here. If signing fails, the mail is rejected with a
+    /// [temporary failure](Error::temporary_failure), so it's retried later.
+    /// Like [`Self::map`], this is skipped by [`Self::process_dry_run`].
+    pub fn dkim_sign(
+        &mut self,
+        selector: impl Into<String>,
+        domain: impl Into<String>,
+        private_key: &[u8],
+        headers: &[&str],
+    ) -> Result<&mut Self, DkimError> {
+        let key = parse_private_key(private_key)?;
+        let selector = selector.into();
+        let domain = domain.into();
+        let headers: Vec<String> = headers.iter().map(|&h| h.to_owned()).collect();
+        self.filter(move |mail| {
+            let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+            let line_ending = mail.line_ending();
+            match signature(
+                mail.contents(),
+                &selector,
+                &domain,
+                &key,
+                &headers,
+                line_ending,
+            ) {
+                Ok(value) => {
+                    mail.prepend_header("DKIM-Signature", &value);
+                    Action::Continue
+                }
+                Err(err) => {
+                    log::info!("{err}");
+                    Action::Reject(Error::temporary_failure("DKIM signing failed"))
+                }
+            }
+        });
+        self.filters.last_mut().unwrap().mutating = true;
+        Ok(self)
+    }
+}
+
+/// The result of verifying one `DKIM-Signature`, see [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimResult {
+    /// The signing domain (`d=`).
+    pub domain: String,
+    /// The selector of the key (`s=`).
+    pub selector: String,
+    /// If the body hash and signature are valid.
+    pub pass: bool,
+}
+
+/// Parse the `tag=value` list of a DKIM header or key record. Whitespace is removed from the
+/// values.
+fn parse_tags(value: &str) -> Vec<(&str, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            let value = value.chars().filter(|c| !c.is_whitespace()).collect();
+            Some((name.trim(), value))
+        })
+        .collect()
+}
+fn get_tag<'a>(tags: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(tag, _)| *tag == name)
+        .map(|(_, value)| value.as_str())
+}
+/// Remove the value of the `b=` tag, as the signature is computed without it.
+fn strip_signature(value: &str) -> String {
+    value
+        .split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((name, _)) if name.trim() == "b" => format!("{name}="),
+            _ => tag.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+/// Parse the public key from the `p=` tag of a DKIM key `record`.
+///
+/// Returns `None` if the key is revoked (empty) or invalid.
+pub fn parse_key_record(record: &str) -> Option<RsaPublicKey> {
+    let tags = parse_tags(record);
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(get_tag(&tags, "p")?)
+        .ok()?;
+    if key.is_empty() {
+        return None;
+    }
+    RsaPublicKey::from_public_key_der(&key)
+        .ok()
+        .or_else(|| RsaPublicKey::from_pkcs1_der(&key).ok())
+}
+
+/// Verify the `DKIM-Signature` header at `idx` of `headers`.
+fn verify_signature(
+    message: &[u8],
+    headers: &[mailparse::MailHeader],
+    body_start: usize,
+    idx: usize,
+    key: Option<RsaPublicKey>,
+    tags: &[(&str, String)],
+) -> bool {
+    let (Some(key), Some("rsa-sha256"), Some(signed), Some(body_hash), Some(signature)) = (
+        key,
+        get_tag(tags, "a"),
+        get_tag(tags, "h"),
+        get_tag(tags, "bh"),
+        get_tag(tags, "b"),
+    ) else {
+        return false;
+    };
+    if get_tag(tags, "c") != Some("relaxed/relaxed") {
+        log::info!("Unsupported DKIM canonicalization");
+        return false;
+    }
+    // RFC 6376 5.4: the From header must be signed
+    if !signed
+        .split(':')
+        .any(|name| name.eq_ignore_ascii_case("from"))
+    {
+        log::info!("DKIM signature doesn't sign From");
+        return false;
+    }
+    if let Some(expires) = get_tag(tags, "x") {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        if expires.parse::<u64>().map_or(true, |expires| expires < now) {
+            log::info!("DKIM signature expired at {expires}");
+            return false;
+        }
+    }
+    let engine = base64::engine::general_purpose::STANDARD;
+    let (Ok(body_hash), Ok(signature)) = (engine.decode(body_hash), engine.decode(signature))
+    else {
+        return false;
+    };
+
+    let mut body = canonicalize_body(&message[body_start..]);
+    if let Some(len) = get_tag(tags, "l").and_then(|l| l.parse().ok()) {
+        if len > body.len() {
+            return false;
+        }
+        body.truncate(len);
+    }
+    if Sha256::digest(&body)[..] != body_hash[..] {
+        log::info!("DKIM body hash mismatch");
+        return false;
+    }
+
+    let mut used = vec![false; headers.len()];
+    let mut data = Vec::new();
+    for name in signed.split(':') {
+        let Some(header) = (0..headers.len())
+            .rev()
+            .find(|&i| !used[i] && headers[i].get_key_ref().eq_ignore_ascii_case(name))
+        else {
+            continue;
+        };
+        used[header] = true;
+        data.extend(canonicalize_header(name, headers[header].get_value_raw()));
+    }
+    let value = String::from_utf8_lossy(headers[idx].get_value_raw());
+    let mut signature_header =
+        canonicalize_header(&headers[idx].get_key(), strip_signature(&value).as_bytes());
+    signature_header.truncate(signature_header.len() - 2);
+    data.extend(signature_header);
+
+    key.verify(
+        Pkcs1v15Sign::new::<Sha256>(),
+        &Sha256::digest(&data),
+        &signature,
+    )
+    .is_ok()
+}
+/// Verify every `DKIM-Signature` of `message`, getting the public keys from `key`, which gets
+/// the selector and domain.
+fn verify_all(
+    message: &[u8],
+    mut key: impl FnMut(&str, &str) -> Option<RsaPublicKey>,
+) -> Vec<DkimResult> {
+    let Ok((headers, body_start)) = mailparse::parse_headers(message) else {
+        return Vec::new();
+    };
+    (0..headers.len())
+        .filter(|&idx| {
+            headers[idx]
+                .get_key_ref()
+                .eq_ignore_ascii_case("dkim-signature")
+        })
+        .map(|idx| {
+            let value = headers[idx].get_value();
+            let tags = parse_tags(&value);
+            let domain = get_tag(&tags, "d").unwrap_or_default().to_owned();
+            let selector = get_tag(&tags, "s").unwrap_or_default().to_owned();
+            let public_key = key(&selector, &domain);
+            let pass = verify_signature(message, &headers, body_start, idx, public_key, &tags);
+            log::info!("DKIM signature of {domain} (selector {selector}): pass? {pass}");
+            DkimResult {
+                domain,
+                selector,
+                pass,
+            }
+        })
+        .collect()
+}
+/// Verify every `DKIM-Signature` of `message` (the headers & body) using `key`, without
+/// looking up keys in DNS.
+///
+/// Only `rsa-sha256` with `relaxed/relaxed` canonicalization is supported; other signatures
+/// fail. As RFC 6376 requires, signatures which don't sign `From` or have expired (`x=`) fail.
+///
+/// This takes the raw message instead of a [`BasicMail`], as the signature covers the bytes as
+/// they were received, and [`BasicMail`] doesn't expose them: only [`UnparsedMail::contents`]
+/// does, and a [`ParsedMail`](crate::ParsedMail) serializes its headers differently.
+pub fn verify_with_key(message: &[u8], key: &RsaPublicKey) -> Vec<DkimResult> {
+    verify_all(message, |_, _| Some(key.clone()))
+}
+/// Verify every `DKIM-Signature` of `message` (the headers & body, e.g. from
+/// [`UnparsedMail::contents`]), getting the keys from DNS (`selector._domainkey.domain`).
+///
+/// This does blocking lookups using the first nameserver in `/etc/resolv.conf`. Signatures
+/// whose key can't be found fail. See [`verify_with_key`], also for why this takes the raw
+/// message.
+#[cfg(feature = "dkim-verify")]
+pub fn verify(message: &[u8]) -> Vec<DkimResult> {
+    verify_all(message, |selector, domain| {
+        let records = dns::lookup_txt(&format!("{selector}._domainkey.{domain}")).ok()?;
+        records.iter().find_map(|record| parse_key_record(record))
+    })
+}
+
+/// A minimal DNS client for `TXT` records, as the standard library can't look them up.
+#[cfg(feature = "dkim-verify")]
+mod dns {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    const TYPE_TXT: u16 = 16;
+
+    fn nameserver() -> SocketAddr {
+        let ip = std::fs::read_to_string("/etc/resolv.conf")
+            .ok()
+            .and_then(|conf| {
+                conf.lines().find_map(|line| {
+                    let ip = line.trim().strip_prefix("nameserver")?.trim();
+                    ip.parse::<IpAddr>().ok()
+                })
+            })
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(ip, 53)
+    }
+    fn invalid() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "invalid DNS response")
+    }
+    /// Skip the (possibly compressed) name at `pos`, returning the position after it.
+    fn skip_name(msg: &[u8], mut pos: usize) -> io::Result<usize> {
+        loop {
+            let len = *msg.get(pos).ok_or_else(invalid)?;
+            if len & 0xc0 == 0xc0 {
+                return Ok(pos + 2);
+            }
+            pos += 1;
+            if len == 0 {
+                return Ok(pos);
+            }
+            pos += len as usize;
+        }
+    }
+    /// Encode `name` as DNS labels, rejecting names which can't be encoded (e.g. from a
+    /// malicious `DKIM-Signature`), instead of corrupting the query.
+    fn encode_name(name: &str) -> io::Result<Vec<u8>> {
+        let invalid_name = || io::Error::new(io::ErrorKind::InvalidInput, "invalid DNS name");
+        let mut encoded = Vec::new();
+        for label in name.trim_end_matches('.').split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(invalid_name());
+            }
+            encoded.push(label.len() as u8);
+            encoded.extend(label.as_bytes());
+        }
+        encoded.push(0);
+        // including the length of the first label
+        if encoded.len() > 255 {
+            return Err(invalid_name());
+        }
+        Ok(encoded)
+    }
+    fn read_u16(msg: &[u8], pos: usize) -> io::Result<u16> {
+        let bytes = msg.get(pos..pos + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    /// Look up the `TXT` records of `name`. The strings of each record are joined.
+    pub(super) fn lookup_txt(name: &str) -> io::Result<Vec<String>> {
+        // a random ID, so off-path attackers can't guess it to spoof responses
+        let mut id = [0; 2];
+        getrandom::getrandom(&mut id).map_err(io::Error::other)?;
+        let mut question = encode_name(name)?;
+        question.extend(TYPE_TXT.to_be_bytes());
+        // class IN
+        question.extend([0, 1]);
+        let mut query = Vec::with_capacity(12 + question.len());
+        query.extend(id);
+        // recursion desired, 1 question
+        query.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query.extend(&question);
+
+        let server = nameserver();
+        // bind to the address family of the server
+        let local = match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        socket.connect(server)?;
+        socket.send(&query)?;
+        let mut msg = vec![0; 4096];
+        let len = socket.recv(&mut msg)?;
+        msg.truncate(len);
+
+        let flags = read_u16(&msg, 2)?;
+        // must be a response to the query
+        if msg[..2] != id || flags & 0x8000 == 0 || read_u16(&msg, 4)? != 1 {
+            return Err(invalid());
+        }
+        // truncated, the records might be incomplete
+        if flags & 0x0200 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated DNS response",
+            ));
+        }
+        match flags & 0x000f {
+            0 => {}
+            // NXDOMAIN
+            3 => return Ok(Vec::new()),
+            rcode => {
+                return Err(io::Error::other(format!(
+                    "DNS lookup failed, rcode {rcode}"
+                )));
+            }
+        }
+        // the question is echoed, the name case-insensitively
+        let echoed = msg.get(12..12 + question.len()).ok_or_else(invalid)?;
+        if !echoed.eq_ignore_ascii_case(&question) {
+            return Err(invalid());
+        }
+        let answers = read_u16(&msg, 6)?;
+        let mut pos = 12 + question.len();
+        let mut records = Vec::new();
+        for _ in 0..answers {
+            pos = skip_name(&msg, pos)?;
+            let kind = read_u16(&msg, pos)?;
+            let data_len = read_u16(&msg, pos + 8)? as usize;
+            pos += 10;
+            let mut data = msg.get(pos..pos + data_len).ok_or_else(invalid)?;
+            pos += data_len;
+            if kind != TYPE_TXT {
+                continue;
+            }
+            let mut record = Vec::new();
+            while let Some((&len, rest)) = data.split_first() {
+                let len = (len as usize).min(rest.len());
+                record.extend(&rest[..len]);
+                data = &rest[len..];
+            }
+            records.push(String::from_utf8_lossy(&record).into_owned());
+        }
+        log::info!("Got {} TXT records for {name}", records.len());
+        Ok(records)
+    }
+}
@@ -0,0 +1,53 @@
+//! Lookups in DNS-based blocklists (DNSBL), such as `zen.spamhaus.org`.
+
+use std::fmt::Write;
+use std::net::{IpAddr, ToSocketAddrs};
+
+use crate::{Action, BasicMail, Error, Filter};
+
+/// Get the name to look up for `ip` in `zone`.
+///
+/// IPv4 addresses have their octets reversed (`1.2.3.4` => `4.3.2.1.zone`) and IPv6 addresses
+/// have their nibbles reversed.
+pub fn query_name(ip: IpAddr, zone: &str) -> String {
+    let mut name = String::new();
+    match ip {
+        IpAddr::V4(ip) => {
+            for octet in ip.octets().iter().rev() {
+                write!(name, "{octet}.").unwrap();
+            }
+        }
+        IpAddr::V6(ip) => {
+            for byte in ip.octets().iter().rev() {
+                write!(name, "{:x}.{:x}.", byte & 0xf, byte >> 4).unwrap();
+            }
+        }
+    }
+    name += zone;
+    name
+}
+/// Check if `ip` is listed in the DNSBL `zone`.
+///
+/// This does a blocking lookup using the system resolver. Errors (e.g. `NXDOMAIN`) are treated
+/// as not listed.
+pub fn check_ip(ip: IpAddr, zone: &str) -> bool {
+    let name = query_name(ip, zone);
+    let listed = (name.as_str(), 0)
+        .to_socket_addrs()
+        .is_ok_and(|mut addrs| addrs.next().is_some());
+    log::info!("Looked up {name}: listed? {listed}");
+    listed
+}
+
+impl<M: BasicMail> Filter<M> {
+    /// Reject the mail with `err` if the client IP is listed in the DNSBL `zone`.
+    ///
+    /// See [`BasicMail::client_ip`]. Mails without a client IP continue.
+    pub fn reject_if_listed(&mut self, zone: impl Into<String>, err: Error) -> &mut Self {
+        let zone = zone.into();
+        self.filter(move |mail| match mail.client_ip() {
+            Some(ip) if check_ip(ip, &zone) => Action::Reject(err.clone()),
+            _ => Action::Continue,
+        })
+    }
+}
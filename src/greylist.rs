@@ -0,0 +1,103 @@
+//! Greylisting: deferring mail from unknown senders, as spam software often doesn't retry.
+//!
+//! See [`Filter::greylist`].
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::{utils, Action, BasicMail, Error, Filter};
+
+/// The triplet a mail is greylisted by: the client IP, the envelope sender and one envelope
+/// recipient.
+///
+/// The addresses are lowercase. The IP is `None` if it couldn't be determined, see
+/// [`BasicMail::client_ip`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GreylistKey {
+    pub ip: Option<IpAddr>,
+    pub sender: String,
+    pub recipient: String,
+}
+
+/// Storage of when each [`GreylistKey`] was first seen, for [`Filter::greylist`].
+///
+/// Implement this to share the state between processes, e.g. in a database. The times are
+/// [`SystemTime`]s, so they can be stored as Unix timestamps.
+pub trait GreylistStore: Send + Sync {
+    /// When `key` was first [recorded](Self::record), if ever.
+    fn seen(&self, key: &GreylistKey) -> Option<SystemTime>;
+    /// Record that `key` was first seen at `at`.
+    fn record(&self, key: &GreylistKey, at: SystemTime);
+}
+
+/// A [`GreylistStore`] in memory.
+///
+/// The state is lost when the process exits, so this only works when the filter runs in a
+/// long-lived process, not when postfix starts a new process for each mail.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    seen: Mutex<HashMap<GreylistKey, SystemTime>>,
+}
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Forget the keys first seen more than `max_age` ago, to limit the memory used.
+    pub fn prune(&self, max_age: Duration) {
+        let mut seen = self.seen.lock().unwrap();
+        let before = seen.len();
+        // keep times in the future, e.g. after the clock was changed
+        seen.retain(|_, at| at.elapsed().map_or(true, |age| age <= max_age));
+        log::info!("Pruned {} greylist entries", before - seen.len());
+    }
+}
+impl GreylistStore for MemoryStore {
+    fn seen(&self, key: &GreylistKey) -> Option<SystemTime> {
+        self.seen.lock().unwrap().get(key).copied()
+    }
+    fn record(&self, key: &GreylistKey, at: SystemTime) {
+        self.seen.lock().unwrap().entry(key.clone()).or_insert(at);
+    }
+}
+
+impl<M: BasicMail> Filter<M> {
+    /// Defer the mail with [`Error::greylist`] the first time its triplet (see [`GreylistKey`]) is
+    /// seen, and until `delay` has passed since then.
+    ///
+    /// Each recipient is checked separately, so the mail is deferred if any of the triplets are
+    /// too new. Senders retrying after `delay` are accepted.
+    pub fn greylist(&mut self, store: impl GreylistStore + 'static, delay: Duration) -> &mut Self {
+        self.filter(move |mail| {
+            let ip = mail.client_ip();
+            let sender = utils::iter_addrs(mail.sender())
+                .next()
+                .map_or_else(String::new, |sender| sender.addr.to_lowercase());
+            let now = SystemTime::now();
+            let mut remaining = Duration::ZERO;
+            for recipient in utils::iter_addrs(mail.recipients()) {
+                let key = GreylistKey {
+                    ip,
+                    sender: sender.clone(),
+                    recipient: recipient.addr.to_lowercase(),
+                };
+                let first = store.seen(&key).unwrap_or_else(|| {
+                    log::info!("First time seeing {key:?}");
+                    store.record(&key, now);
+                    now
+                });
+                // a first time in the future (the clock was changed) counts as just seen
+                let waited = now.duration_since(first).unwrap_or(Duration::ZERO);
+                remaining = remaining.max(delay.saturating_sub(waited));
+            }
+            if remaining.is_zero() {
+                return Action::Continue;
+            }
+            // round up, so the sender doesn't retry too early
+            let minutes = remaining.as_secs().div_ceil(60).max(1);
+            log::info!("Greylisted for {minutes} more minutes");
+            Action::Reject(Error::greylist(minutes.try_into().unwrap_or(u32::MAX)))
+        })
+    }
+}
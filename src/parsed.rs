@@ -0,0 +1,694 @@
+//! A fully parsed mail, for when the [`UnparsedMail`](crate::UnparsedMail) isn't enough.
+
+use std::collections::BTreeMap;
+
+use mailparse::MailAddrList;
+
+use crate::{
+    detect_line_ending, is_bulk_precedence, list_id_of, media_type_of, message_ids, parse_date,
+    set_recipient_header, set_sender_header, utils, BasicMail, MailParts, RecipientDisclosure,
+    StructuredMail,
+};
+
+/// Decode the raw `value` of the header `key`, as [`mailparse::MailHeader::get_value`] does.
+fn decode_header(key: &str, value: &str) -> String {
+    let raw = format!("{key}: {value}");
+    mailparse::parse_header(raw.as_bytes())
+        .map_or_else(|_| value.to_owned(), |(header, _)| header.get_value())
+}
+/// Parse the raw `value` of the header `key` as a list of addresses.
+fn parse_addrs(key: &str, value: Option<&str>) -> MailAddrList {
+    (|| {
+        let raw = format!("{key}: {}", value?);
+        let (header, _) = mailparse::parse_header(raw.as_bytes()).ok()?;
+        mailparse::addrparse_header(&header).ok()
+    })()
+    .unwrap_or_else(|| MailAddrList::from(Vec::new()))
+}
+/// Get the body of `part` without decoding the `Content-Transfer-Encoding`.
+fn raw_body(part: &mailparse::ParsedMail) -> Vec<u8> {
+    use mailparse::body::Body;
+    match part.get_body_encoded() {
+        Body::Base64(body) | Body::QuotedPrintable(body) => body.get_raw().to_vec(),
+        Body::SevenBit(body) | Body::EightBit(body) => body.get_raw().to_vec(),
+        Body::Binary(body) => body.get_raw().to_vec(),
+    }
+}
+
+/// An attachment of a mail, see [`StructuredMail::attachments`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    /// The decoded filename, see [`MailPart::filename`].
+    pub filename: Option<String>,
+    /// The MIME type, e.g. `application/pdf`.
+    pub content_type: String,
+    /// The size of the decoded content, in bytes.
+    pub size: usize,
+}
+
+/// The parsed `Content-Type` of a [`MailPart`].
+///
+/// Like [`mailparse::ParsedContentType`], which can't be cloned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// The lowercase MIME type, e.g. `text/plain`.
+    pub mimetype: String,
+    /// The lowercase charset, e.g. `utf-8`.
+    pub charset: String,
+    /// The other parameters, e.g. `boundary`.
+    pub params: BTreeMap<String, String>,
+}
+impl ContentType {
+    /// Parse the value of a `Content-Type` header, see [`mailparse::parse_content_type`].
+    pub fn parse(value: &str) -> Self {
+        Self::from(&mailparse::parse_content_type(value))
+    }
+}
+impl From<&mailparse::ParsedContentType> for ContentType {
+    fn from(ctype: &mailparse::ParsedContentType) -> Self {
+        Self {
+            mimetype: ctype.mimetype.clone(),
+            charset: ctype.charset.clone(),
+            params: ctype.params.clone(),
+        }
+    }
+}
+
+/// Find the index after the closing delimiter `--boundary--` in `raw`, at the start of a line.
+fn closing_delimiter_end(raw: &[u8], boundary: &str) -> Option<usize> {
+    let delimiter = format!("--{boundary}--");
+    let mut pos = 0;
+    while let Some(idx) = memchr::memmem::find(&raw[pos..], delimiter.as_bytes()) {
+        let idx = pos + idx;
+        if idx == 0 || raw[idx - 1] == b'\n' {
+            return Some(idx + delimiter.len());
+        }
+        pos = idx + 1;
+    }
+    None
+}
+
+/// A part of the MIME tree of a [`ParsedMail`].
+///
+/// The root part holds the headers of the mail.
+#[derive(Debug, Clone)]
+pub struct MailPart {
+    /// The headers of this part, in order. The values are raw, as they appear in the mail.
+    pub headers: Vec<(String, String)>,
+    /// The `Content-Type` of this part.
+    pub ctype: ContentType,
+    /// The body, still encoded with the `Content-Transfer-Encoding`.
+    ///
+    /// For multipart parts, this is the preamble before the first part, see [`Self::subparts`].
+    pub body: Vec<u8>,
+    /// The parts of a multipart part.
+    pub subparts: Vec<MailPart>,
+    /// The epilogue of a multipart part, after the closing delimiter (including the line
+    /// ending of that line).
+    pub epilogue: Vec<u8>,
+}
+impl MailPart {
+    fn from_parsed(part: &mailparse::ParsedMail) -> Self {
+        let headers = part
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    header.get_key(),
+                    String::from_utf8_lossy(header.get_value_raw()).into_owned(),
+                )
+            })
+            .collect();
+        let subparts: Vec<_> = part.subparts.iter().map(Self::from_parsed).collect();
+        // for multipart parts, the body is the preamble
+        let body = raw_body(part);
+        let epilogue = part
+            .ctype
+            .params
+            .get("boundary")
+            .filter(|_| !subparts.is_empty())
+            .and_then(|boundary| {
+                let end = closing_delimiter_end(part.raw_bytes, boundary)?;
+                Some(part.raw_bytes[end..].to_vec())
+            })
+            .unwrap_or_default();
+        Self {
+            headers,
+            ctype: ContentType::from(&part.ctype),
+            body,
+            subparts,
+            epilogue,
+        }
+    }
+
+    /// Get the raw value of the first header named `key` (case-insensitive).
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+    /// Like [`Self::header`], but decodes the value (e.g. RFC 2047 encoded words).
+    pub fn header_decoded(&self, key: &str) -> Option<String> {
+        self.header(key).map(|value| decode_header(key, value))
+    }
+    /// Serialize the headers & body of this part, for decoding the body using [`mailparse`].
+    fn to_leaf_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for (key, value) in &self.headers {
+            raw.extend_from_slice(format!("{key}: {value}\r\n").as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        raw.extend_from_slice(&self.body);
+        raw
+    }
+    /// If this part has a `Content-Disposition` of `attachment`.
+    pub fn is_attachment(&self) -> bool {
+        self.header("content-disposition").is_some_and(|value| {
+            mailparse::parse_content_disposition(value).disposition
+                == mailparse::DispositionType::Attachment
+        })
+    }
+    /// Get the body, decoded from the `Content-Transfer-Encoding` and charset.
+    pub fn decoded_body(&self) -> Result<String, mailparse::MailParseError> {
+        let raw = self.to_leaf_bytes();
+        mailparse::parse_mail(&raw)?.get_body()
+    }
+    /// Like [`Self::decoded_body`], but without decoding the charset.
+    pub fn decoded_body_raw(&self) -> Result<Vec<u8>, mailparse::MailParseError> {
+        let raw = self.to_leaf_bytes();
+        mailparse::parse_mail(&raw)?.get_body_raw()
+    }
+    /// Get the filename, from the `Content-Disposition` or the `name` of the `Content-Type`.
+    ///
+    /// RFC 2231 parameters and RFC 2047 encoded words are decoded.
+    pub fn filename(&self) -> Option<String> {
+        let disposition = self
+            .header("content-disposition")
+            .map(mailparse::parse_content_disposition);
+        let filename = disposition
+            .and_then(|disposition| disposition.params.get("filename").cloned())
+            .or_else(|| self.ctype.params.get("name").cloned())?;
+        Some(decode_header("filename", &filename))
+    }
+    /// Get all attachments in this part and its subparts.
+    ///
+    /// Parts with a `Content-Disposition` of `attachment` or with a [filename](Self::filename)
+    /// are attachments.
+    pub fn attachments(&self) -> Vec<Attachment> {
+        self.iter()
+            .filter(|part| part.subparts.is_empty())
+            .filter_map(|part| {
+                let filename = part.filename();
+                if filename.is_none() && !part.is_attachment() {
+                    return None;
+                }
+                let size = part
+                    .decoded_body_raw()
+                    .map_or(part.body.len(), |body| body.len());
+                Some(Attachment {
+                    filename,
+                    content_type: part.ctype.mimetype.clone(),
+                    size,
+                })
+            })
+            .collect()
+    }
+    /// Get the first non-multipart part (depth-first) with the `mimetype` which isn't an
+    /// attachment.
+    ///
+    /// For `multipart/alternative`, this picks the alternative with the `mimetype`.
+    pub fn find_body(&self, mimetype: &str) -> Option<&MailPart> {
+        self.iter().find(|part| {
+            part.subparts.is_empty()
+                && part.ctype.mimetype.eq_ignore_ascii_case(mimetype)
+                && !part.is_attachment()
+        })
+    }
+    /// Iterate over this part and all its subparts, depth-first.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &MailPart> + '_> {
+        Box::new(std::iter::once(self).chain(self.subparts.iter().flat_map(MailPart::iter)))
+    }
+
+    /// Serialize this part and all its subparts.
+    ///
+    /// Unmodified parts are written as they were parsed, except for the whitespace after the
+    /// `:` of headers.
+    fn write_to(&self, out: &mut Vec<u8>, line_ending: &str) {
+        for (key, value) in &self.headers {
+            out.extend_from_slice(key.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.extend_from_slice(line_ending.as_bytes());
+        }
+        out.extend_from_slice(line_ending.as_bytes());
+        let boundary = self.ctype.params.get("boundary");
+        let Some(boundary) = boundary.filter(|_| !self.subparts.is_empty()) else {
+            out.extend_from_slice(&self.body);
+            return;
+        };
+        // The line ending before a delimiter belongs to the delimiter, but the parsed parts
+        // (and preamble) keep it at their end. Only add one if it's missing.
+        let start_line = |out: &mut Vec<u8>| {
+            if !out.ends_with(b"\n") {
+                out.extend_from_slice(line_ending.as_bytes());
+            }
+        };
+        out.extend_from_slice(&self.body);
+        for part in &self.subparts {
+            start_line(out);
+            out.extend_from_slice(format!("--{boundary}{line_ending}").as_bytes());
+            part.write_to(out, line_ending);
+        }
+        start_line(out);
+        out.extend_from_slice(format!("--{boundary}--").as_bytes());
+        if self.epilogue.is_empty() {
+            out.extend_from_slice(line_ending.as_bytes());
+        } else {
+            out.extend_from_slice(&self.epilogue);
+        }
+    }
+}
+
+/// A parsed representation of a mail, giving access to the MIME tree.
+///
+/// Create it using [`UnparsedMail::parse`](crate::UnparsedMail::parse). The mail is serialized
+/// again in [`BasicMail::into_parts`], so prefer the [`UnparsedMail`](crate::UnparsedMail) if you
+/// only make few changes.
+#[derive(Clone)]
+pub struct ParsedMail {
+    root: MailPart,
+    line_ending: &'static str,
+    size: usize,
+
+    from: MailAddrList,
+    to: MailAddrList,
+
+    recipients: Option<MailAddrList>,
+    sender: Option<MailAddrList>,
+    sender_header: Option<MailAddrList>,
+    cc: Option<MailAddrList>,
+    bcc: Option<MailAddrList>,
+    reply_to: Option<MailAddrList>,
+    subject: Option<String>,
+    user_agent: Option<String>,
+    message_id: Option<String>,
+    date: Option<String>,
+    parsed_date: Option<Option<time::OffsetDateTime>>,
+    list_id: Option<String>,
+    is_bulk: Option<bool>,
+    content_type: Option<String>,
+    return_path: Option<String>,
+    in_reply_to: Option<String>,
+    references: Option<Vec<String>>,
+    body_text: Option<Option<String>>,
+    body_html: Option<Option<String>>,
+}
+impl ParsedMail {
+    pub fn new(
+        buf: &[u8],
+        from: MailAddrList,
+        to: MailAddrList,
+    ) -> Result<Self, mailparse::MailParseError> {
+        let parsed = mailparse::parse_mail(buf)?;
+        Ok(Self::from_root(
+            MailPart::from_parsed(&parsed),
+            detect_line_ending(buf),
+            buf.len(),
+            from,
+            to,
+        ))
+    }
+    /// Create the mail from the MIME tree, without parsing.
+    pub(crate) fn from_root(
+        root: MailPart,
+        line_ending: &'static str,
+        size: usize,
+        from: MailAddrList,
+        to: MailAddrList,
+    ) -> Self {
+        Self {
+            root,
+            line_ending,
+            size,
+
+            from,
+            to,
+
+            recipients: None,
+            sender: None,
+            sender_header: None,
+            cc: None,
+            bcc: None,
+            reply_to: None,
+            subject: None,
+            user_agent: None,
+            message_id: None,
+            date: None,
+            parsed_date: None,
+            list_id: None,
+            is_bulk: None,
+            content_type: None,
+            return_path: None,
+            in_reply_to: None,
+            references: None,
+            body_text: None,
+            body_html: None,
+        }
+    }
+
+    /// Serialize the (possibly modified) mail.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.root.write_to(&mut out, self.line_ending);
+        out
+    }
+
+    /// [Sanitize](utils::sanitize_header_value) `s`, folding with [`Self::line_ending`] so
+    /// CRLF mails don't get bare LFs.
+    fn header_value_of(&self, s: &str) -> String {
+        let s = utils::sanitize_header_value(s);
+        match self.line_ending {
+            "\n" => s.into_owned(),
+            line_ending => s.replace('\n', line_ending),
+        }
+    }
+    fn clear_cache(&mut self) {
+        self.recipients = None;
+        self.sender = None;
+        self.sender_header = None;
+        self.cc = None;
+        self.bcc = None;
+        self.reply_to = None;
+        self.subject = None;
+        self.user_agent = None;
+        self.message_id = None;
+        self.date = None;
+        self.parsed_date = None;
+        self.list_id = None;
+        self.is_bulk = None;
+        self.content_type = None;
+        self.return_path = None;
+        self.in_reply_to = None;
+        self.references = None;
+        self.body_text = None;
+        self.body_html = None;
+    }
+}
+impl BasicMail for ParsedMail {
+    /// The size of the mail when it was parsed. Changes aren't taken into account.
+    fn size(&self) -> usize {
+        self.size
+    }
+    fn into_parts(self) -> MailParts {
+        MailParts {
+            body: self.to_bytes(),
+            from: self.from,
+            to: self.to,
+        }
+    }
+
+    fn header_domain(&mut self) -> Option<&str> {
+        let domain = utils::domain_of(self.header_recipients())?;
+        log::info!("Got domain: {domain}");
+        Some(domain)
+    }
+    fn domain(&mut self) -> Option<&str> {
+        let domain = utils::domain_of(self.recipients())?;
+        log::info!("Got domain: {domain}");
+        Some(domain)
+    }
+    fn header_recipients(&mut self) -> &MailAddrList {
+        let addrs = self
+            .recipients
+            .get_or_insert_with(|| parse_addrs("to", self.root.header("to")));
+        log::info!("Got header recipients: {addrs}");
+        addrs
+    }
+    fn header_sender(&mut self) -> &MailAddrList {
+        let addrs = self
+            .sender
+            .get_or_insert_with(|| parse_addrs("from", self.root.header("from")));
+        log::info!("Got header senders: {addrs}");
+        addrs
+    }
+    fn recipients(&mut self) -> &MailAddrList {
+        let addrs = &self.to;
+        log::info!("Got recipients: {addrs}");
+        addrs
+    }
+    fn sender(&mut self) -> &MailAddrList {
+        let addrs = &self.from;
+        log::info!("Got senders: {addrs}");
+        addrs
+    }
+    fn sender_header(&mut self) -> &MailAddrList {
+        self.sender_header
+            .get_or_insert_with(|| parse_addrs("sender", self.root.header("sender")))
+    }
+    fn cc(&mut self) -> &MailAddrList {
+        self.cc
+            .get_or_insert_with(|| parse_addrs("cc", self.root.header("cc")))
+    }
+    fn bcc(&mut self) -> &MailAddrList {
+        self.bcc
+            .get_or_insert_with(|| parse_addrs("bcc", self.root.header("bcc")))
+    }
+    fn reply_to(&mut self) -> &MailAddrList {
+        self.reply_to
+            .get_or_insert_with(|| parse_addrs("reply-to", self.root.header("reply-to")))
+    }
+    fn subject(&mut self) -> &str {
+        self.subject
+            .get_or_insert_with(|| self.root.header_decoded("subject").unwrap_or_default())
+    }
+    fn user_agent(&mut self) -> Option<&str> {
+        let s = self
+            .user_agent
+            .get_or_insert_with(|| self.root.header_decoded("user-agent").unwrap_or_default());
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    fn message_id(&mut self) -> Option<&str> {
+        let s = self.message_id.get_or_insert_with(|| {
+            let id = self.root.header_decoded("message-id").unwrap_or_default();
+            utils::strip_angle_brackets(&id).to_owned()
+        });
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    fn date(&mut self) -> Option<time::OffsetDateTime> {
+        if let Some(date) = self.parsed_date {
+            return date;
+        }
+        let date = self.date_raw().and_then(parse_date);
+        *self.parsed_date.insert(date)
+    }
+    fn date_raw(&mut self) -> Option<&str> {
+        let s = self.date.get_or_insert_with(|| {
+            let date = self.root.header_decoded("date").unwrap_or_default();
+            date.trim().to_owned()
+        });
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+
+    fn list_id(&mut self) -> Option<&str> {
+        let s = self.list_id.get_or_insert_with(|| {
+            let id = self.root.header_decoded("list-id").unwrap_or_default();
+            list_id_of(&id).to_owned()
+        });
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    fn in_reply_to(&mut self) -> Option<&str> {
+        let s = self.in_reply_to.get_or_insert_with(|| {
+            let value = self.root.header_decoded("in-reply-to").unwrap_or_default();
+            message_ids(&value).into_iter().next().unwrap_or_default()
+        });
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    fn references(&mut self) -> Vec<String> {
+        self.references
+            .get_or_insert_with(|| {
+                let value = self.root.header_decoded("references").unwrap_or_default();
+                message_ids(&value)
+            })
+            .clone()
+    }
+    fn return_path(&mut self) -> Option<&str> {
+        let s = self.return_path.get_or_insert_with(|| {
+            let path = self.root.header_decoded("return-path").unwrap_or_default();
+            utils::strip_angle_brackets(&path).to_owned()
+        });
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    fn content_type(&mut self) -> Option<&str> {
+        let s = self.content_type.get_or_insert_with(|| {
+            let value = self.root.header_decoded("content-type").unwrap_or_default();
+            media_type_of(&value)
+        });
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    fn is_bulk(&mut self) -> bool {
+        *self.is_bulk.get_or_insert_with(|| {
+            self.root
+                .header_decoded("precedence")
+                .is_some_and(|p| is_bulk_precedence(&p))
+                || self.root.header("list-unsubscribe").is_some()
+        })
+    }
+
+    fn set_header(&mut self, header: &str, s: &str) {
+        let s = self.header_value_of(s);
+        if let Some((_, value)) = self
+            .root
+            .headers
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(header))
+        {
+            *value = s;
+        }
+        self.clear_cache();
+    }
+    fn set_header_all(&mut self, header: &str, s: &str) {
+        let s = self.header_value_of(s);
+        for (_, value) in self
+            .root
+            .headers
+            .iter_mut()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(header))
+        {
+            value.clone_from(&s);
+        }
+        self.clear_cache();
+    }
+    fn get_all_headers(&mut self, header: &str) -> Vec<String> {
+        self.root
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(header))
+            .map(|(k, v)| decode_header(k, v))
+            .collect()
+    }
+    fn raw_headers(&mut self) -> Vec<(String, String)> {
+        self.root
+            .headers
+            .iter()
+            .map(|(k, v)| (k.clone(), decode_header(k, v)))
+            .collect()
+    }
+    fn header_value(&mut self, header: &str) -> Option<String> {
+        self.root.header_decoded(header)
+    }
+    fn header_value_lossy(&mut self, header: &str) -> String {
+        self.root.header(header).map_or_else(String::new, |value| {
+            utils::decode_header_lossy(value.as_bytes())
+        })
+    }
+    fn received_count(&mut self) -> usize {
+        self.root
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("received"))
+            .count()
+    }
+    fn has_header(&mut self, header: &str) -> bool {
+        self.root.header(header).is_some()
+    }
+    fn append_header(&mut self, header: &str, value: &str) {
+        if !utils::is_valid_header_name(header) {
+            log::info!("Not appending header with invalid name {header:?}");
+            return;
+        }
+        let value = self.header_value_of(value);
+        self.root.headers.push((header.to_owned(), value));
+        self.clear_cache();
+    }
+    fn prepend_header(&mut self, header: &str, value: &str) {
+        if !utils::is_valid_header_name(header) {
+            log::info!("Not prepending header with invalid name {header:?}");
+            return;
+        }
+        let value = self.header_value_of(value);
+        self.root.headers.insert(0, (header.to_owned(), value));
+        self.clear_cache();
+    }
+    fn remove_header(&mut self, header: &str) {
+        if let Some(idx) = self
+            .root
+            .headers
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(header))
+        {
+            self.root.headers.remove(idx);
+        }
+        self.clear_cache();
+    }
+
+    fn set_recipient(
+        &mut self,
+        recipients: impl Into<MailAddrList>,
+        disclosure: RecipientDisclosure,
+    ) {
+        let recipients = recipients.into();
+        set_recipient_header(self, &recipients, disclosure);
+        self.to = recipients;
+    }
+    fn set_sender(&mut self, sender: impl Into<MailAddrList>, rewrite_header: bool) {
+        let sender = sender.into();
+        if rewrite_header {
+            set_sender_header(self, &sender);
+        }
+        self.from = sender;
+    }
+}
+impl StructuredMail for ParsedMail {
+    fn root_part(&self) -> &MailPart {
+        &self.root
+    }
+    fn root_part_mut(&mut self) -> &mut MailPart {
+        self.clear_cache();
+        &mut self.root
+    }
+    fn body_text(&mut self) -> Option<&str> {
+        self.body_text
+            .get_or_insert_with(|| {
+                let part = self.root.find_body("text/plain")?;
+                part.decoded_body().ok()
+            })
+            .as_deref()
+    }
+    fn body_html(&mut self) -> Option<&str> {
+        self.body_html
+            .get_or_insert_with(|| {
+                let part = self.root.find_body("text/html")?;
+                part.decoded_body().ok()
+            })
+            .as_deref()
+    }
+}
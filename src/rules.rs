@@ -0,0 +1,103 @@
+//! Filters defined in configuration files, for deployments without custom Rust code.
+//!
+//! The [`Rule`]s are deserialized using [`serde`] (e.g. from TOML or JSON) and compiled to a
+//! [`Filter`] using [`Filter::from_rules`].
+//!
+//! In JSON, a rule looks like `{ "type": "max_size", "bytes": 10000000 }`.
+
+use serde::Deserialize;
+
+use crate::{utils, Action, BasicMail, Error, Filter};
+
+/// A configurable filter, see [`Filter::from_rules`].
+///
+/// Rules which reject use [`Error::policy_rejection`] with `message`, or a generic message if
+/// it's not set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Rule {
+    /// Reject mail from senders (in the envelope) at `domain`.
+    RejectSenderDomain {
+        domain: String,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Reject mail larger than `bytes`, see [`Filter::reject_if_larger_than`].
+    MaxSize { bytes: usize },
+    /// Reject mail with a subject matching the regex `pattern`.
+    SubjectMatches {
+        pattern: String,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Reject mail where the value of `header` matches the regex `pattern`, see
+    /// [`Filter::reject_if_header_matches`].
+    HeaderMatches {
+        header: String,
+        pattern: String,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Set `header` to `value`, see [`BasicMail::set_header`].
+    SetHeader { header: String, value: String },
+    /// Remove `header`, see [`BasicMail::remove_header`].
+    RemoveHeader { header: String },
+}
+
+fn rejection(message: Option<String>) -> Error {
+    Error::policy_rejection(message.unwrap_or_else(|| "Rejected by policy".to_owned()))
+}
+
+impl<M: BasicMail> Filter<M> {
+    /// Create a filter running `rules` in order.
+    ///
+    /// Returns an error if any of the regexes are invalid.
+    pub fn from_rules(rules: Vec<Rule>) -> Result<Self, regex::Error> {
+        let mut filter = Self::new();
+        for rule in rules {
+            match rule {
+                Rule::RejectSenderDomain { domain, message } => {
+                    let err = rejection(message);
+                    filter.filter(move |mail| {
+                        if utils::iter_addrs(mail.sender())
+                            .any(|sender| utils::domain_eq(&sender.addr, &domain))
+                        {
+                            log::info!("Sender domain {domain:?} is rejected");
+                            Action::Reject(err.clone())
+                        } else {
+                            Action::Continue
+                        }
+                    });
+                }
+                Rule::MaxSize { bytes } => {
+                    filter.reject_if_larger_than(bytes);
+                }
+                Rule::SubjectMatches { pattern, message } => {
+                    filter.reject_if_header_matches(
+                        "subject",
+                        regex::Regex::new(&pattern)?,
+                        rejection(message),
+                    );
+                }
+                Rule::HeaderMatches {
+                    header,
+                    pattern,
+                    message,
+                } => {
+                    filter.reject_if_header_matches(
+                        header,
+                        regex::Regex::new(&pattern)?,
+                        rejection(message),
+                    );
+                }
+                Rule::SetHeader { header, value } => {
+                    filter.map(move |mail| mail.set_header(&header, &value));
+                }
+                Rule::RemoveHeader { header } => {
+                    filter.map(move |mail| mail.remove_header(&header));
+                }
+            }
+        }
+        Ok(filter)
+    }
+}
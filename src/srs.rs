@@ -0,0 +1,130 @@
+//! Sender Rewriting Scheme (SRS), for forwarding mail without breaking SPF.
+//!
+//! The format and hash follow the common convention (as in `libsrs2` & `postsrsd`), so other
+//! mail servers can reverse the addresses.
+
+use std::fmt::{self, Display};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+
+use crate::{utils, BasicMail};
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// The number of characters of the hash to use.
+const HASH_LEN: usize = 4;
+/// The number of days a rewritten address can be [decoded](decode).
+pub const MAX_AGE_DAYS: u64 = 21;
+
+/// Error when decoding a SRS address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SrsError {
+    /// The address isn't a `SRS0` address.
+    NotSrs,
+    /// The address is missing parts.
+    Malformed,
+    /// The hash doesn't match. The address was probably forged.
+    InvalidHash,
+    /// The timestamp is older than [`MAX_AGE_DAYS`].
+    Expired,
+}
+impl Display for SrsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotSrs => "not a SRS address",
+            Self::Malformed => "malformed SRS address",
+            Self::InvalidHash => "invalid SRS hash",
+            Self::Expired => "SRS address expired",
+        })
+    }
+}
+impl std::error::Error for SrsError {}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+        / (60 * 60 * 24)
+}
+fn encode_timestamp(days: u64) -> String {
+    let timestamp = days % 1024;
+    [timestamp >> 5, timestamp & 31]
+        .iter()
+        .map(|&idx| BASE32[idx as usize] as char)
+        .collect()
+}
+fn decode_timestamp(timestamp: &str) -> Option<u64> {
+    timestamp.bytes().try_fold(0, |acc, c| {
+        let idx = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())?;
+        Some((acc << 5) | idx as u64)
+    })
+}
+/// HMAC-SHA1 of the lowercase `data`, encoded as base64 and truncated to [`HASH_LEN`].
+fn hash(secret: &[u8], data: &[&str]) -> String {
+    let mut mac =
+        Hmac::<sha1::Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    for data in data {
+        mac.update(data.to_ascii_lowercase().as_bytes());
+    }
+    let digest = mac.finalize().into_bytes();
+    let mut hash = base64::engine::general_purpose::STANDARD.encode(&digest[..]);
+    hash.truncate(HASH_LEN);
+    hash
+}
+
+/// Rewrite `sender` to `SRS0=hash=tt=domain=local@forwarding_domain`.
+///
+/// `secret` has to be the same when [decoding](decode) the address.
+/// If `sender` isn't an address (e.g. the null sender of bounces), it's returned unchanged.
+pub fn encode(sender: &str, forwarding_domain: &str, secret: &[u8]) -> String {
+    encode_at(sender, forwarding_domain, secret, today())
+}
+fn encode_at(sender: &str, forwarding_domain: &str, secret: &[u8], days: u64) -> String {
+    let Some((local, domain)) = sender.rsplit_once('@') else {
+        return sender.to_owned();
+    };
+    let timestamp = encode_timestamp(days);
+    let hash = hash(secret, &[&timestamp, domain, local]);
+    format!("SRS0={hash}={timestamp}={domain}={local}@{forwarding_domain}")
+}
+/// Get the original sender of an address created by [`encode`].
+pub fn decode(addr: &str, secret: &[u8]) -> Result<String, SrsError> {
+    decode_at(addr, secret, today())
+}
+fn decode_at(addr: &str, secret: &[u8], days: u64) -> Result<String, SrsError> {
+    let (local, _) = addr.rsplit_once('@').ok_or(SrsError::NotSrs)?;
+    let is_srs = local.len() > 5
+        && local
+            .get(..4)
+            .is_some_and(|s| s.eq_ignore_ascii_case("srs0"))
+        && matches!(local.as_bytes()[4], b'=' | b'+' | b'-');
+    if !is_srs {
+        return Err(SrsError::NotSrs);
+    }
+    let mut parts = local[5..].splitn(4, '=');
+    let mut next = || parts.next().ok_or(SrsError::Malformed);
+    let (hash, timestamp, domain, local) = (next()?, next()?, next()?, next()?);
+
+    if !self::hash(secret, &[timestamp, domain, local]).eq_ignore_ascii_case(hash) {
+        return Err(SrsError::InvalidHash);
+    }
+    let timestamp = decode_timestamp(timestamp).ok_or(SrsError::Malformed)?;
+    let age = (days % 1024 + 1024 - timestamp) % 1024;
+    if age > MAX_AGE_DAYS {
+        return Err(SrsError::Expired);
+    }
+    Ok(format!("{local}@{domain}"))
+}
+
+/// Rewrite the envelope sender of `mail` using [`encode`]. The `From` header is kept.
+///
+/// See [`BasicMail::set_sender`].
+pub fn rewrite_sender(mail: &mut impl BasicMail, forwarding_domain: &str, secret: &[u8]) {
+    let Some(sender) = utils::iter_addrs(mail.sender()).next() else {
+        return;
+    };
+    let sender = encode(&sender.addr, forwarding_domain, secret);
+    log::info!("Rewrote sender to {sender}");
+    mail.set_sender(utils::addr_single(sender), false);
+}
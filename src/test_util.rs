@@ -0,0 +1,237 @@
+//! Mails built in memory, for unit testing filters without writing raw messages.
+//!
+//! ```
+//! use smtp_filter::test_util::TestMail;
+//! use smtp_filter::{Action, BasicMail, Filter, Outcome};
+//!
+//! let mut filter = Filter::new();
+//! filter.filter(|mail: &mut TestMail| {
+//!     if mail.subject().is_empty() {
+//!         Action::Reject(smtp_filter::Error::new(554, None, "Missing subject"))
+//!     } else {
+//!         Action::Continue
+//!     }
+//! });
+//!
+//! let mut mail = TestMail::new()
+//!     .with_sender("alice@example.com")
+//!     .with_recipients("bob@example.org")
+//!     .with_subject("Hello");
+//! assert!(matches!(filter.process_ref(&mut mail), Outcome::Delivered(_)));
+//! ```
+
+use mailparse::MailAddrList;
+
+use crate::parsed::{ContentType, MailPart, ParsedMail};
+use crate::{utils, BasicMail, MailParts, RecipientDisclosure, StructuredMail};
+
+/// A mail with fields set directly, see the [module-level documentation](self).
+///
+/// Setting the sender or recipients sets both the envelope and the headers.
+/// [`BasicMail::into_parts`] serializes the headers & body to a minimal message.
+#[derive(Clone)]
+pub struct TestMail {
+    mail: ParsedMail,
+}
+impl TestMail {
+    /// Create a mail without headers, body or envelope addresses.
+    pub fn new() -> Self {
+        let root = MailPart {
+            headers: Vec::new(),
+            ctype: ContentType::parse("text/plain; charset=utf-8"),
+            body: Vec::new(),
+            subparts: Vec::new(),
+            epilogue: Vec::new(),
+        };
+        let empty = || MailAddrList::from(Vec::new());
+        Self {
+            mail: ParsedMail::from_root(root, "\r\n", 0, empty(), empty()),
+        }
+    }
+
+    /// Append the header `key`.
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.mail.append_header(key, value);
+        self
+    }
+    /// Set the `Subject` header.
+    pub fn with_subject(mut self, subject: &str) -> Self {
+        if !self.mail.has_header("subject") {
+            self.mail.append_header("Subject", "");
+        }
+        self.mail.set_subject(subject);
+        self
+    }
+    /// Set the envelope sender and the `From` header.
+    ///
+    /// `sender` is parsed as an address list, e.g. `Alice <alice@example.com>`.
+    pub fn with_sender(mut self, sender: &str) -> Self {
+        if !self.mail.has_header("from") {
+            self.mail.append_header("From", "");
+        }
+        self.mail.set_sender(parse_list(sender), true);
+        self
+    }
+    /// Set the envelope recipients and the `To` header.
+    ///
+    /// `recipients` is parsed as a comma-separated address list.
+    pub fn with_recipients(mut self, recipients: &str) -> Self {
+        if !self.mail.has_header("to") {
+            self.mail.append_header("To", "");
+        }
+        self.mail
+            .set_recipient(parse_list(recipients), RecipientDisclosure::Open);
+        self
+    }
+    /// Set the plain text body.
+    pub fn with_body(mut self, body: &str) -> Self {
+        if !self.mail.has_header("content-type") {
+            self.mail
+                .append_header("Content-Type", "text/plain; charset=utf-8");
+            self.mail.append_header("Content-Transfer-Encoding", "8bit");
+        }
+        // only convert bare LFs, so CRLF bodies aren't given an extra CR
+        let body = body.replace("\r\n", "\n").replace('\n', "\r\n");
+        self.mail.root_part_mut().body = body.into_bytes();
+        self
+    }
+}
+impl Default for TestMail {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_list(s: &str) -> MailAddrList {
+    mailparse::addrparse(s).unwrap_or_else(|_| utils::addr_single(s.trim()))
+}
+
+impl BasicMail for TestMail {
+    /// The size of the serialized mail.
+    fn size(&self) -> usize {
+        self.mail.to_bytes().len()
+    }
+    fn into_parts(self) -> MailParts {
+        self.mail.into_parts()
+    }
+    fn header_domain(&mut self) -> Option<&str> {
+        self.mail.header_domain()
+    }
+    fn domain(&mut self) -> Option<&str> {
+        self.mail.domain()
+    }
+    fn header_recipients(&mut self) -> &MailAddrList {
+        self.mail.header_recipients()
+    }
+    fn header_sender(&mut self) -> &MailAddrList {
+        self.mail.header_sender()
+    }
+    fn recipients(&mut self) -> &MailAddrList {
+        self.mail.recipients()
+    }
+    fn sender(&mut self) -> &MailAddrList {
+        self.mail.sender()
+    }
+    fn sender_header(&mut self) -> &MailAddrList {
+        self.mail.sender_header()
+    }
+    fn cc(&mut self) -> &MailAddrList {
+        self.mail.cc()
+    }
+    fn bcc(&mut self) -> &MailAddrList {
+        self.mail.bcc()
+    }
+    fn reply_to(&mut self) -> &MailAddrList {
+        self.mail.reply_to()
+    }
+    fn subject(&mut self) -> &str {
+        self.mail.subject()
+    }
+    fn user_agent(&mut self) -> Option<&str> {
+        self.mail.user_agent()
+    }
+    fn message_id(&mut self) -> Option<&str> {
+        self.mail.message_id()
+    }
+    fn date(&mut self) -> Option<time::OffsetDateTime> {
+        self.mail.date()
+    }
+    fn date_raw(&mut self) -> Option<&str> {
+        self.mail.date_raw()
+    }
+    fn list_id(&mut self) -> Option<&str> {
+        self.mail.list_id()
+    }
+    fn in_reply_to(&mut self) -> Option<&str> {
+        self.mail.in_reply_to()
+    }
+    fn references(&mut self) -> Vec<String> {
+        self.mail.references()
+    }
+    fn return_path(&mut self) -> Option<&str> {
+        self.mail.return_path()
+    }
+    fn content_type(&mut self) -> Option<&str> {
+        self.mail.content_type()
+    }
+    fn is_bulk(&mut self) -> bool {
+        self.mail.is_bulk()
+    }
+    fn received_count(&mut self) -> usize {
+        self.mail.received_count()
+    }
+    fn set_header(&mut self, header: &str, s: &str) {
+        self.mail.set_header(header, s)
+    }
+    fn set_header_all(&mut self, header: &str, s: &str) {
+        self.mail.set_header_all(header, s)
+    }
+    fn get_all_headers(&mut self, header: &str) -> Vec<String> {
+        self.mail.get_all_headers(header)
+    }
+    fn raw_headers(&mut self) -> Vec<(String, String)> {
+        self.mail.raw_headers()
+    }
+    fn header_value(&mut self, header: &str) -> Option<String> {
+        self.mail.header_value(header)
+    }
+    fn header_value_lossy(&mut self, header: &str) -> String {
+        self.mail.header_value_lossy(header)
+    }
+    fn has_header(&mut self, header: &str) -> bool {
+        self.mail.has_header(header)
+    }
+    fn append_header(&mut self, header: &str, value: &str) {
+        self.mail.append_header(header, value)
+    }
+    fn prepend_header(&mut self, header: &str, value: &str) {
+        self.mail.prepend_header(header, value)
+    }
+    fn remove_header(&mut self, header: &str) {
+        self.mail.remove_header(header)
+    }
+    fn set_recipient(
+        &mut self,
+        recipients: impl Into<MailAddrList>,
+        disclosure: RecipientDisclosure,
+    ) {
+        self.mail.set_recipient(recipients, disclosure)
+    }
+    fn set_sender(&mut self, sender: impl Into<MailAddrList>, rewrite_header: bool) {
+        self.mail.set_sender(sender, rewrite_header)
+    }
+}
+impl StructuredMail for TestMail {
+    fn root_part(&self) -> &MailPart {
+        self.mail.root_part()
+    }
+    fn root_part_mut(&mut self) -> &mut MailPart {
+        self.mail.root_part_mut()
+    }
+    fn body_text(&mut self) -> Option<&str> {
+        self.mail.body_text()
+    }
+    fn body_html(&mut self) -> Option<&str> {
+        self.mail.body_html()
+    }
+}